@@ -0,0 +1,12 @@
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Network(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Network(err)
+    }
+}
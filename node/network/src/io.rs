@@ -0,0 +1,88 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use substrate_network_libp2p::{NodeIndex, PeerId, ProtocolId, Service as NetworkService};
+
+/// Tracks bytes sent (recorded by `NetSyncIo`) and received (recorded by the
+/// service task), shared by every `NetSyncIo` instance created for a given
+/// `Service` so metrics reflect the whole service, not a single tick's worth
+/// of traffic.
+#[derive(Default)]
+pub struct BandwidthSink {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl BandwidthSink {
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
+
+/// Everything `Protocol<T>` needs from the transport: sending/disconnecting
+/// by `NodeIndex`, plus resolving a connection slot to the peer's stable
+/// identity. Kept as a trait (rather than hard-coding `NetSyncIo`) so tests
+/// can drive `Protocol<T>` against an in-memory fake instead of a live
+/// `NetworkService`.
+pub trait NetworkIo {
+    fn send_message(&mut self, node_index: NodeIndex, data: Vec<u8>);
+    fn disconnect_peer(&mut self, node_index: NodeIndex);
+    /// Stable identity of whoever currently holds `node_index`, if still
+    /// connected. `NodeIndex` itself is a connection-slot number that gets
+    /// reused for the next peer to dial in, so anything that needs to
+    /// persist across a reconnect (reputation, bans, ...) must key off this
+    /// instead.
+    fn peer_id(&self, node_index: NodeIndex) -> Option<PeerId>;
+}
+
+/// Thin wrapper around the raw `NetworkService` that scopes every call to a
+/// single registered protocol, so `Protocol<T>` never has to juggle
+/// `ProtocolId`s itself.
+pub struct NetSyncIo {
+    network_service: Arc<Mutex<NetworkService>>,
+    protocol_id: ProtocolId,
+    bandwidth: Arc<BandwidthSink>,
+}
+
+impl NetSyncIo {
+    pub fn new(
+        network_service: &Arc<Mutex<NetworkService>>,
+        protocol_id: ProtocolId,
+        bandwidth: Arc<BandwidthSink>,
+    ) -> NetSyncIo {
+        NetSyncIo {
+            network_service: network_service.clone(),
+            protocol_id,
+            bandwidth,
+        }
+    }
+}
+
+impl NetworkIo for NetSyncIo {
+    fn send_message(&mut self, node_index: NodeIndex, data: Vec<u8>) {
+        self.bandwidth.record_out(data.len());
+        self.network_service
+            .lock()
+            .send_custom_message(node_index, self.protocol_id, data);
+    }
+
+    fn disconnect_peer(&mut self, node_index: NodeIndex) {
+        self.network_service.lock().drop_node(node_index);
+    }
+
+    fn peer_id(&self, node_index: NodeIndex) -> Option<PeerId> {
+        self.network_service.lock().peer_id_of_node(node_index)
+    }
+}
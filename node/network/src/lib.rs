@@ -0,0 +1,24 @@
+extern crate bincode;
+extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate parking_lot;
+extern crate primitives;
+extern crate rand;
+#[macro_use]
+extern crate serde_derive;
+extern crate substrate_network_libp2p;
+extern crate tokio;
+
+#[cfg(test)]
+extern crate test_utils;
+
+pub mod error;
+pub mod io;
+pub mod protocol;
+pub mod service;
+
+pub use error::Error;
+pub use io::NetworkIo;
+pub use protocol::{PeerAction, Protocol, ProtocolConfig, RpcRequest, RpcResponse, Transaction};
+pub use service::{Metrics, NetworkCommand, Service};
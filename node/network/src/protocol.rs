@@ -0,0 +1,711 @@
+use futures::sync::oneshot;
+use io::NetworkIo;
+use parking_lot::RwLock;
+use primitives::traits::GenericResult;
+use rand::{self, Rng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use substrate_network_libp2p::{NodeIndex, PeerId};
+
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Target, low and high watermarks for the gossip mesh maintained per topic.
+/// Mirrors the libp2p gossipsub defaults: the protocol tries to keep the
+/// mesh at `MESH_TARGET` peers and only intervenes once it drifts outside
+/// `[MESH_LOW, MESH_HIGH]`.
+const MESH_TARGET: usize = 6;
+const MESH_LOW: usize = 4;
+const MESH_HIGH: usize = 12;
+/// Fraction of non-mesh peers that receive an IHAVE announcement on each
+/// maintenance tick.
+const GOSSIP_FACTOR: f64 = 0.25;
+/// How long a message id is kept in the de-duplication cache before it is
+/// considered forgotten and can be gossiped about / re-requested again.
+const SEEN_MESSAGE_TTL: Duration = Duration::from_secs(120);
+
+/// Reputation score at or below which a peer is disconnected and banned.
+const BAN_REPUTATION_THRESHOLD: i32 = -100;
+/// How long a banned peer is refused reconnection for.
+const BAN_DURATION: Duration = Duration::from_secs(30);
+/// How long a peer's reputation entry is kept since it was last updated
+/// before it is evicted, so a peer that connects briefly and never
+/// reoffends doesn't leave a permanent entry behind.
+const REPUTATION_TTL: Duration = Duration::from_secs(3600);
+
+/// Protocol-level events a peer can be penalized or rewarded for.
+#[derive(Debug, Clone, Copy)]
+pub enum PeerAction {
+    ValidMessage,
+    MalformedMessage,
+    InvalidTransaction,
+}
+
+/// How long an outstanding request/response RPC waits for a reply before it
+/// is reaped and the caller's receiver is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type RequestId = u64;
+
+/// Pull-based counterpart to the gossip publish/subscribe flow: lets a peer
+/// ask another one directly for data it is missing instead of waiting for
+/// it to be broadcast.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcRequest {
+    /// Opaque, handler-defined request payload (e.g. "block by hash").
+    Custom(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcResponse {
+    /// Opaque, handler-defined response payload.
+    Custom(Vec<u8>),
+}
+
+impl PeerAction {
+    fn score_delta(self) -> i32 {
+        match self {
+            PeerAction::ValidMessage => 1,
+            PeerAction::MalformedMessage => -50,
+            PeerAction::InvalidTransaction => -100,
+        }
+    }
+}
+
+pub type Topic = String;
+pub type MessageId = u64;
+
+pub trait Transaction: Send + Sync + Serialize + DeserializeOwned + Clone + 'static {}
+impl<T> Transaction for T where T: Send + Sync + Serialize + DeserializeOwned + Clone + 'static {}
+
+#[derive(Clone, Default)]
+pub struct ProtocolConfig {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GossipMessage<T> {
+    /// A payload published to `topic`, tagged with the id its publisher
+    /// computed so downstream peers can dedupe it.
+    Publish { topic: Topic, id: MessageId, payload: T },
+    /// Ask the receiver to add us to its mesh for `topic`.
+    Graft { topic: Topic },
+    /// Ask the receiver to drop us from its mesh for `topic`.
+    Prune { topic: Topic },
+    /// Announce message ids we have seen recently, so peers outside our
+    /// mesh can pull anything they are missing.
+    IHave { topic: Topic, ids: Vec<MessageId> },
+    /// Request full payloads for the given ids from whoever announced them.
+    IWant { ids: Vec<MessageId> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Message<T> {
+    Transaction(T),
+    Gossip(GossipMessage<T>),
+    Request { id: RequestId, request: RpcRequest },
+    Response { id: RequestId, response: RpcResponse },
+}
+
+#[derive(Default)]
+struct TopicState {
+    subscribed: bool,
+    mesh: HashSet<NodeIndex>,
+}
+
+pub struct Protocol<T> {
+    config: ProtocolConfig,
+    peers: RwLock<HashSet<NodeIndex>>,
+    topics: RwLock<HashMap<Topic, TopicState>>,
+    seen_messages: RwLock<HashMap<MessageId, (Instant, T)>>,
+    tx_callback: fn(T) -> GenericResult,
+    // Keyed by `PeerId`, not `NodeIndex`: a `NodeIndex` is a connection-slot
+    // number that gets reused for the next peer to dial in once the current
+    // occupant disconnects, so reputation/bans must survive under the
+    // peer's stable identity rather than the slot it happened to occupy.
+    // Each entry also tracks when it was last touched so `expire_reputation`
+    // can evict peers that have been quiet for a while, the same way
+    // `seen_messages`/`banned` age out their own entries — otherwise a node
+    // that mints unlimited `PeerId`s and connects briefly would leave a
+    // permanent entry behind.
+    reputation: RwLock<HashMap<PeerId, (i32, Instant)>>,
+    banned: RwLock<HashMap<PeerId, Instant>>,
+    rpc_handler: fn(RpcRequest) -> RpcResponse,
+    next_request_id: AtomicU64,
+    outstanding_requests: RwLock<HashMap<RequestId, (Instant, oneshot::Sender<RpcResponse>)>>,
+}
+
+impl<T: Transaction> Protocol<T> {
+    pub fn new(
+        config: ProtocolConfig,
+        tx_callback: fn(T) -> GenericResult,
+        rpc_handler: fn(RpcRequest) -> RpcResponse,
+    ) -> Protocol<T> {
+        Protocol {
+            config,
+            peers: RwLock::new(HashSet::new()),
+            topics: RwLock::new(HashMap::new()),
+            seen_messages: RwLock::new(HashMap::new()),
+            tx_callback,
+            reputation: RwLock::new(HashMap::new()),
+            banned: RwLock::new(HashMap::new()),
+            rpc_handler,
+            next_request_id: AtomicU64::new(0),
+            outstanding_requests: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Send `request` to `node_index` and return an id plus a future that
+    /// resolves once the matching response arrives (or is dropped if the
+    /// request times out, see `expire_requests`).
+    pub fn send_request<N: NetworkIo>(
+        &self,
+        net_sync: &mut N,
+        node_index: NodeIndex,
+        request: RpcRequest,
+    ) -> (RequestId, oneshot::Receiver<RpcResponse>) {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        self.outstanding_requests
+            .write()
+            .insert(id, (Instant::now(), sender));
+        self.send(net_sync, node_index, &Message::Request { id, request });
+        (id, receiver)
+    }
+
+    fn expire_requests(&self) {
+        let now = Instant::now();
+        self.outstanding_requests
+            .write()
+            .retain(|_, (sent_at, _)| now.duration_since(*sent_at) < REQUEST_TIMEOUT);
+    }
+
+    /// Apply a reputation delta for whoever currently holds `node_index`.
+    /// Peers whose score drops to or below `BAN_REPUTATION_THRESHOLD` are
+    /// disconnected and banned (by `PeerId`) for `BAN_DURATION`. If the peer
+    /// id can no longer be resolved (e.g. it already disconnected) the
+    /// report is dropped rather than risking attributing it to whichever
+    /// peer takes the freed slot next.
+    pub fn report_peer<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex, action: PeerAction) {
+        let peer_id = match net_sync.peer_id(node_index) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        if self.apply_reputation(&peer_id, action) {
+            net_sync.disconnect_peer(node_index);
+        }
+    }
+
+    /// Pure bookkeeping for `report_peer`, split out so it can be tested
+    /// without a live `NetworkIo`. Returns whether the peer should be
+    /// disconnected as a result.
+    fn apply_reputation(&self, peer_id: &PeerId, action: PeerAction) -> bool {
+        let now = Instant::now();
+        let score = {
+            let mut reputation = self.reputation.write();
+            let entry = reputation.entry(peer_id.clone()).or_insert((0, now));
+            entry.0 += action.score_delta();
+            entry.1 = now;
+            entry.0
+        };
+        if score <= BAN_REPUTATION_THRESHOLD {
+            self.banned
+                .write()
+                .insert(peer_id.clone(), Instant::now() + BAN_DURATION);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned
+            .read()
+            .get(peer_id)
+            .map_or(false, |expiry| Instant::now() < *expiry)
+    }
+
+    fn expire_bans(&self) {
+        let now = Instant::now();
+        self.banned.write().retain(|_, expiry| now < *expiry);
+    }
+
+    /// Evict reputation entries that haven't been touched in `REPUTATION_TTL`,
+    /// so a peer that connects briefly (and is never banned) doesn't leave a
+    /// permanent entry behind.
+    fn expire_reputation(&self) {
+        let now = Instant::now();
+        self.reputation
+            .write()
+            .retain(|_, (_, last_updated)| now.duration_since(*last_updated) < REPUTATION_TTL);
+    }
+
+    /// Number of peers currently known to be connected.
+    pub fn peer_count(&self) -> usize {
+        self.peers.read().len()
+    }
+
+    pub fn outstanding_request_count(&self) -> usize {
+        self.outstanding_requests.read().len()
+    }
+
+    pub fn sample_peers(&self, count: usize) -> Vec<NodeIndex> {
+        let peers = self.peers.read();
+        let mut rng = rand::thread_rng();
+        let mut sample: Vec<NodeIndex> = peers.iter().cloned().collect();
+        rng.shuffle(&mut sample);
+        sample.truncate(count);
+        sample
+    }
+
+    pub fn send_message<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex, transaction: &T) {
+        self.send(net_sync, node_index, &Message::Transaction(transaction.clone()));
+    }
+
+    /// Subscribe to `topic`, so peers are grafted into its mesh (on connect
+    /// and in `regraft_meshes`) and future `publish` calls on it reach them.
+    /// `publish` itself does not check the subscription: it sends to whatever
+    /// peers the topic's mesh holds, including peers that grafted themselves.
+    pub fn subscribe(&self, topic: &Topic) {
+        let mut topics = self.topics.write();
+        topics.entry(topic.clone()).or_insert_with(TopicState::default).subscribed = true;
+    }
+
+    /// Publish `payload` under `topic`, forwarding it to every peer in that
+    /// topic's mesh.
+    pub fn publish<N: NetworkIo>(&self, net_sync: &mut N, topic: &Topic, payload: T) {
+        let id = message_id(&payload);
+        self.seen_messages.write().insert(id, (Instant::now(), payload.clone()));
+        let mesh: Vec<NodeIndex> = {
+            let topics = self.topics.read();
+            topics
+                .get(topic)
+                .map(|state| state.mesh.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+        let message = Message::Gossip(GossipMessage::Publish {
+            topic: topic.clone(),
+            id,
+            payload,
+        });
+        for peer in mesh {
+            self.send(net_sync, peer, &message);
+        }
+    }
+
+    pub fn on_message<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex, data: &[u8]) {
+        let message: Message<T> = match bincode::deserialize(data) {
+            Ok(m) => m,
+            Err(e) => {
+                debug!(target: "network", "could not decode message from {}: {:?}", node_index, e);
+                self.report_peer(net_sync, node_index, PeerAction::MalformedMessage);
+                return;
+            }
+        };
+        match message {
+            Message::Transaction(transaction) => match (self.tx_callback)(transaction) {
+                Ok(()) => self.report_peer(net_sync, node_index, PeerAction::ValidMessage),
+                Err(_) => self.report_peer(net_sync, node_index, PeerAction::InvalidTransaction),
+            },
+            Message::Gossip(gossip) => self.on_gossip_message(net_sync, node_index, gossip),
+            Message::Request { id, request } => {
+                let response = (self.rpc_handler)(request);
+                self.send(net_sync, node_index, &Message::Response { id, response });
+            }
+            Message::Response { id, response } => {
+                if let Some((_, sender)) = self.outstanding_requests.write().remove(&id) {
+                    let _ = sender.send(response);
+                }
+            }
+        }
+    }
+
+    fn on_gossip_message<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex, gossip: GossipMessage<T>) {
+        match gossip {
+            GossipMessage::Publish { topic, id, payload } => {
+                let is_new = !self.seen_messages.read().contains_key(&id);
+                if !is_new {
+                    return;
+                }
+                self.seen_messages
+                    .write()
+                    .insert(id, (Instant::now(), payload.clone()));
+                match (self.tx_callback)(payload.clone()) {
+                    Ok(()) => self.report_peer(net_sync, node_index, PeerAction::ValidMessage),
+                    Err(_) => self.report_peer(net_sync, node_index, PeerAction::InvalidTransaction),
+                }
+                let mesh: Vec<NodeIndex> = {
+                    let topics = self.topics.read();
+                    topics
+                        .get(&topic)
+                        .map(|state| state.mesh.iter().cloned().filter(|p| *p != node_index).collect())
+                        .unwrap_or_default()
+                };
+                let message = Message::Gossip(GossipMessage::Publish { topic, id, payload });
+                for peer in mesh {
+                    self.send(net_sync, peer, &message);
+                }
+            }
+            GossipMessage::Graft { topic } => {
+                let mut topics = self.topics.write();
+                let state = topics.entry(topic).or_insert_with(TopicState::default);
+                if state.mesh.len() < MESH_HIGH {
+                    state.mesh.insert(node_index);
+                }
+            }
+            GossipMessage::Prune { topic } => {
+                if let Some(state) = self.topics.write().get_mut(&topic) {
+                    state.mesh.remove(&node_index);
+                }
+            }
+            GossipMessage::IHave { topic, ids } => {
+                let missing: Vec<MessageId> = {
+                    let seen = self.seen_messages.read();
+                    ids.into_iter().filter(|id| !seen.contains_key(id)).collect()
+                };
+                if !missing.is_empty() {
+                    let _ = topic;
+                    self.send(net_sync, node_index, &Message::Gossip(GossipMessage::IWant { ids: missing }));
+                }
+            }
+            GossipMessage::IWant { ids } => {
+                let seen = self.seen_messages.read();
+                for id in ids {
+                    if let Some((_, payload)) = seen.get(&id) {
+                        // We don't know which topic this id belongs to any
+                        // more at this point, so it is sent with an empty
+                        // topic: the receiver dedupes and processes the
+                        // payload but finds no mesh to forward it to.
+                        let message = Message::Gossip(GossipMessage::Publish {
+                            topic: String::new(),
+                            id,
+                            payload: payload.clone(),
+                        });
+                        self.send(net_sync, node_index, &message);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn on_peer_connected<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex) {
+        if let Some(peer_id) = net_sync.peer_id(node_index) {
+            if self.is_banned(&peer_id) {
+                net_sync.disconnect_peer(node_index);
+                return;
+            }
+        }
+        self.peers.write().insert(node_index);
+        let mut topics = self.topics.write();
+        for (topic, state) in topics.iter_mut() {
+            if state.subscribed && state.mesh.len() < MESH_TARGET {
+                state.mesh.insert(node_index);
+                self.send(net_sync, node_index, &Message::Gossip(GossipMessage::Graft { topic: topic.clone() }));
+            }
+        }
+    }
+
+    pub fn on_peer_disconnected(&self, node_index: NodeIndex) {
+        self.peers.write().remove(&node_index);
+        let mut topics = self.topics.write();
+        for state in topics.values_mut() {
+            state.mesh.remove(&node_index);
+        }
+    }
+
+    pub fn maintain_peers<N: NetworkIo>(&self, net_sync: &mut N) {
+        self.regraft_meshes(net_sync);
+        self.gossip_recent_messages(net_sync);
+        self.expire_seen_messages();
+        self.expire_bans();
+        self.expire_reputation();
+        self.expire_requests();
+    }
+
+    /// Top up any mesh that fell below `MESH_LOW` and prune any that grew
+    /// past `MESH_HIGH`.
+    fn regraft_meshes<N: NetworkIo>(&self, net_sync: &mut N) {
+        let peers = self.peers.read().clone();
+        let mut topics = self.topics.write();
+        for (topic, state) in topics.iter_mut() {
+            if !state.subscribed {
+                continue;
+            }
+            if state.mesh.len() < MESH_LOW {
+                let candidates: Vec<NodeIndex> = peers.iter().cloned().filter(|p| !state.mesh.contains(p)).collect();
+                let mut rng = rand::thread_rng();
+                let mut candidates = candidates;
+                rng.shuffle(&mut candidates);
+                for peer in candidates.into_iter().take(MESH_TARGET - state.mesh.len()) {
+                    state.mesh.insert(peer);
+                    self.send(net_sync, peer, &Message::Gossip(GossipMessage::Graft { topic: topic.clone() }));
+                }
+            } else if state.mesh.len() > MESH_HIGH {
+                let excess = state.mesh.len() - MESH_TARGET;
+                let to_prune: Vec<NodeIndex> = state.mesh.iter().cloned().take(excess).collect();
+                for peer in to_prune {
+                    state.mesh.remove(&peer);
+                    self.send(net_sync, peer, &Message::Gossip(GossipMessage::Prune { topic: topic.clone() }));
+                }
+            }
+        }
+    }
+
+    /// Send IHAVE announcements of recently seen message ids to a random
+    /// subset of peers outside the mesh, so they can IWANT anything they
+    /// missed.
+    fn gossip_recent_messages<N: NetworkIo>(&self, net_sync: &mut N) {
+        let ids: Vec<MessageId> = self.seen_messages.read().keys().cloned().collect();
+        if ids.is_empty() {
+            return;
+        }
+        let peers = self.peers.read().clone();
+        let topics = self.topics.read();
+        for (topic, state) in topics.iter() {
+            let non_mesh: Vec<NodeIndex> = peers.iter().cloned().filter(|p| !state.mesh.contains(p)).collect();
+            let gossip_count = ((non_mesh.len() as f64) * GOSSIP_FACTOR).ceil() as usize;
+            let mut rng = rand::thread_rng();
+            let mut targets = non_mesh;
+            rng.shuffle(&mut targets);
+            for peer in targets.into_iter().take(gossip_count) {
+                self.send(
+                    net_sync,
+                    peer,
+                    &Message::Gossip(GossipMessage::IHave {
+                        topic: topic.clone(),
+                        ids: ids.clone(),
+                    }),
+                );
+            }
+        }
+    }
+
+    fn expire_seen_messages(&self) {
+        let now = Instant::now();
+        self.seen_messages
+            .write()
+            .retain(|_, (seen_at, _)| now.duration_since(*seen_at) < SEEN_MESSAGE_TTL);
+    }
+
+    fn send<N: NetworkIo>(&self, net_sync: &mut N, node_index: NodeIndex, message: &Message<T>) {
+        match bincode::serialize(message) {
+            Ok(data) => net_sync.send_message(node_index, data),
+            Err(e) => debug!(target: "network", "could not encode message for {}: {:?}", node_index, e),
+        }
+    }
+}
+
+fn message_id<T: Serialize>(payload: &T) -> MessageId {
+    let mut hasher = DefaultHasher::new();
+    if let Ok(bytes) = bincode::serialize(payload) {
+        bytes.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::collections::HashMap as StdHashMap;
+
+    /// In-memory stand-in for `NetSyncIo`: records what would have been sent
+    /// or disconnected instead of touching a real `NetworkService`, so the
+    /// pure `Protocol<T>` logic can be exercised without libp2p. Connected
+    /// peers are registered explicitly via `connect`, mirroring how
+    /// `NetworkService` hands out `NodeIndex` slots that get reused once a
+    /// peer disconnects.
+    #[derive(Default)]
+    struct FakeNetworkIo {
+        connected: StdHashMap<NodeIndex, PeerId>,
+        sent: Vec<(NodeIndex, Vec<u8>)>,
+        disconnected: Vec<NodeIndex>,
+    }
+
+    impl FakeNetworkIo {
+        fn connect(&mut self, node_index: NodeIndex, peer_id: PeerId) {
+            self.connected.insert(node_index, peer_id);
+        }
+
+        fn disconnect(&mut self, node_index: NodeIndex) {
+            self.connected.remove(&node_index);
+        }
+    }
+
+    impl NetworkIo for FakeNetworkIo {
+        fn send_message(&mut self, node_index: NodeIndex, data: Vec<u8>) {
+            self.sent.push((node_index, data));
+        }
+
+        fn disconnect_peer(&mut self, node_index: NodeIndex) {
+            self.disconnected.push(node_index);
+            self.connected.remove(&node_index);
+        }
+
+        fn peer_id(&self, node_index: NodeIndex) -> Option<PeerId> {
+            self.connected.get(&node_index).cloned()
+        }
+    }
+
+    fn test_protocol() -> Protocol<Vec<u8>> {
+        fn tx_callback(_tx: Vec<u8>) -> GenericResult {
+            Ok(())
+        }
+        fn rpc_handler(_req: RpcRequest) -> RpcResponse {
+            RpcResponse::Custom(vec![])
+        }
+        Protocol::new(ProtocolConfig::default(), tx_callback, rpc_handler)
+    }
+
+    /// The bug this guards against: `node_index` is a connection slot that
+    /// gets reused once a peer disconnects. Banning must stick to the
+    /// original peer's `PeerId`, not the slot, so an unrelated peer that
+    /// later reconnects into the same freed slot is not penalized for
+    /// someone else's bad behavior.
+    #[test]
+    fn ban_does_not_follow_a_reused_node_index_to_a_different_peer() {
+        let protocol = test_protocol();
+        let mut io = FakeNetworkIo::default();
+
+        let slot: NodeIndex = 7;
+        let misbehaving_peer = PeerId::random();
+        let innocent_peer = PeerId::random();
+
+        io.connect(slot, misbehaving_peer.clone());
+        protocol.on_peer_connected(&mut io, slot);
+
+        // Misbehave enough to cross the ban threshold and get disconnected.
+        for _ in 0..3 {
+            protocol.report_peer(&mut io, slot, PeerAction::InvalidTransaction);
+        }
+        assert!(io.disconnected.contains(&slot));
+        assert!(protocol.is_banned(&misbehaving_peer));
+
+        // The peer disconnects; its slot is freed and handed to someone new.
+        io.disconnect(slot);
+        protocol.on_peer_disconnected(slot);
+        io.disconnected.clear();
+        io.connect(slot, innocent_peer.clone());
+
+        // The new occupant of the slot must not inherit the ban.
+        assert!(!protocol.is_banned(&innocent_peer));
+        protocol.on_peer_connected(&mut io, slot);
+        assert!(!io.disconnected.contains(&slot));
+        assert!(protocol.peer_count() >= 1);
+    }
+
+    #[test]
+    fn regraft_meshes_tops_up_below_mesh_low() {
+        let protocol = test_protocol();
+        let mut io = FakeNetworkIo::default();
+        let topic = "blocks".to_string();
+
+        // Connect peers *before* subscribing, so `on_peer_connected`'s own
+        // graft-on-connect logic (which only grafts into meshes of already-
+        // subscribed topics) has nothing to do here and the mesh starts
+        // empty — below `MESH_LOW` — by the time `regraft_meshes` runs.
+        for node_index in 0..(MESH_TARGET as NodeIndex + 2) {
+            io.connect(node_index, PeerId::random());
+            protocol.on_peer_connected(&mut io, node_index);
+        }
+        protocol.subscribe(&topic);
+        assert_eq!(protocol.topics.read().get(&topic).unwrap().mesh.len(), 0);
+
+        io.sent.clear();
+        protocol.regraft_meshes(&mut io);
+
+        let grafted: HashSet<NodeIndex> = {
+            let topics = protocol.topics.read();
+            topics.get(&topic).unwrap().mesh.iter().cloned().collect()
+        };
+        assert!(grafted.len() >= MESH_LOW && grafted.len() <= MESH_HIGH);
+        assert_eq!(grafted.len(), MESH_TARGET);
+
+        // Every peer added to the mesh must have actually been sent a Graft.
+        for peer in grafted {
+            let got_graft = io.sent.iter().any(|(node, data)| {
+                *node == peer
+                    && match bincode::deserialize::<Message<Vec<u8>>>(data) {
+                        Ok(Message::Gossip(GossipMessage::Graft { topic: t })) => t == topic,
+                        _ => false,
+                    }
+            });
+            assert!(got_graft, "peer {} added to mesh without receiving a Graft", peer);
+        }
+    }
+
+    #[test]
+    fn duplicate_publish_is_not_rebroadcast() {
+        let protocol = test_protocol();
+        let mut io = FakeNetworkIo::default();
+        let topic = "blocks".to_string();
+        protocol.subscribe(&topic);
+
+        // Two distinct mesh members: `sender` is where the Publish arrives
+        // from (and so is filtered out of the forward step), `other` is a
+        // genuine forwarding target whose receipt count proves the first
+        // publish actually went somewhere and the second was suppressed.
+        let sender: NodeIndex = 1;
+        let other: NodeIndex = 2;
+        io.connect(sender, PeerId::random());
+        io.connect(other, PeerId::random());
+        protocol.on_peer_connected(&mut io, sender);
+        protocol.on_peer_connected(&mut io, other);
+        io.sent.clear();
+
+        let payload = vec![1, 2, 3];
+        let id = message_id(&payload);
+        let gossip = GossipMessage::Publish { topic: topic.clone(), id, payload: payload.clone() };
+        protocol.on_gossip_message(&mut io, sender, gossip.clone());
+        let forwards_to_other = |io: &FakeNetworkIo| io.sent.iter().filter(|(node, _)| *node == other).count();
+        assert_eq!(forwards_to_other(&io), 1, "first publish should be forwarded to the other mesh member");
+
+        // Same id again: must be suppressed, not forwarded a second time.
+        protocol.on_gossip_message(&mut io, sender, gossip);
+        assert_eq!(forwards_to_other(&io), 1);
+    }
+
+    #[test]
+    fn iwant_pulls_a_missing_message_by_id() {
+        let protocol = test_protocol();
+        let mut io = FakeNetworkIo::default();
+
+        let holder: NodeIndex = 1;
+        io.connect(holder, PeerId::random());
+        protocol.on_peer_connected(&mut io, holder);
+
+        let payload = vec![9, 9, 9];
+        let id = message_id(&payload);
+        protocol
+            .seen_messages
+            .write()
+            .insert(id, (Instant::now(), payload.clone()));
+
+        let requester: NodeIndex = 2;
+        io.connect(requester, PeerId::random());
+        protocol.on_gossip_message(&mut io, requester, GossipMessage::IWant { ids: vec![id] });
+
+        assert!(io.sent.iter().any(|(node, _)| *node == requester));
+    }
+
+    #[test]
+    fn expired_requests_are_reaped() {
+        let protocol = test_protocol();
+        let (sender, receiver) = oneshot::channel();
+        let id = protocol.next_request_id.fetch_add(1, Ordering::SeqCst);
+        protocol
+            .outstanding_requests
+            .write()
+            .insert(id, (Instant::now() - REQUEST_TIMEOUT - Duration::from_secs(1), sender));
+        assert_eq!(protocol.outstanding_request_count(), 1);
+
+        protocol.expire_requests();
+
+        assert_eq!(protocol.outstanding_request_count(), 0);
+        // The sender was dropped along with the expired entry, so the
+        // caller's receiver resolves to a cancellation rather than hanging.
+        assert!(receiver.wait().is_err());
+    }
+}
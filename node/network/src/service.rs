@@ -1,104 +1,304 @@
 use error::Error;
+use futures::sync::{mpsc, oneshot};
 use futures::{self, stream, Future, Stream};
-use io::NetSyncIo;
+use io::{BandwidthSink, NetSyncIo, NetworkIo};
 use parking_lot::Mutex;
 use primitives::traits::GenericResult;
-use protocol::{self, Protocol, ProtocolConfig, Transaction};
-use std::io;
+use protocol::{self, PeerAction, Protocol, ProtocolConfig, RpcRequest, RpcResponse, Transaction};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use substrate_network_libp2p::{
-    start_service, NetworkConfiguration, ProtocolId, RegisteredProtocol, Service as NetworkService,
-    ServiceEvent,
+    start_service, Multiaddr, NetworkConfiguration, NodeIndex, ProtocolId, RegisteredProtocol,
+    Service as NetworkService, ServiceEvent,
 };
 use tokio::timer::Interval;
 
 const TICK_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Name of the file, relative to the configured data directory, that the
+/// discovered peer table is persisted to.
+const PEERS_FILE: &str = "known_peers";
+/// How often the metrics snapshot is refreshed.
+const METRICS_TICK: Duration = Duration::from_secs(1);
+/// Topic that `NetworkCommand::AnnounceTransaction` publishes to.
+const TRANSACTIONS_TOPIC: &str = "transactions";
+
+/// Commands external callers can push onto a running `Service` without
+/// reaching into its internals, via the sender returned by `Service::new`.
+pub enum NetworkCommand<T> {
+    /// Publish a transaction to the gossip mesh.
+    AnnounceTransaction(T),
+    /// Dial an additional peer address.
+    Dial(Multiaddr),
+    /// Drop the connection to a peer.
+    Disconnect(NodeIndex),
+    /// Apply a reputation delta to a peer, as if the protocol handler had
+    /// observed the corresponding behavior.
+    ReportPeer(NodeIndex, PeerAction),
+    /// Issue an RPC request to a peer. The response (or nothing, if the
+    /// request times out and is reaped by `maintain_peers`) is delivered on
+    /// the caller-supplied sender, so callers don't need to reach into
+    /// `Service::protocol` to drive `Protocol::send_request` themselves.
+    SendRequest(NodeIndex, RpcRequest, oneshot::Sender<RpcResponse>),
+}
+
+/// Point-in-time snapshot of the network's health, refreshed every
+/// `METRICS_TICK` so `Service::metrics()` is a cheap read rather than a
+/// computation over live locks.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub peer_count: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub custom_messages: u64,
+    pub opened_protocols: u64,
+    pub closed_protocols: u64,
+    pub outstanding_requests: usize,
+}
+
+#[derive(Default)]
+struct MessageCounters {
+    custom_messages: AtomicU64,
+    opened_protocols: AtomicU64,
+    closed_protocols: AtomicU64,
+}
 
 #[allow(dead_code)]
 pub struct Service<T> {
     network: Arc<Mutex<NetworkService>>,
     protocol: Arc<Protocol<T>>,
+    bandwidth: Arc<BandwidthSink>,
+    metrics: Arc<Mutex<Metrics>>,
 }
 
 impl<T: Transaction> Service<T> {
     pub fn new(
         config: ProtocolConfig,
-        net_config: NetworkConfiguration,
+        mut net_config: NetworkConfiguration,
         protocol_id: ProtocolId,
         tx_callback: fn(T) -> GenericResult,
-    ) -> Result<(Service<T>, impl Future<Item = (), Error = ()>), Error> {
+        rpc_handler: fn(RpcRequest) -> RpcResponse,
+        data_dir: Option<PathBuf>,
+    ) -> Result<
+        (
+            Service<T>,
+            mpsc::UnboundedSender<NetworkCommand<T>>,
+            impl Future<Item = (), Error = ()>,
+        ),
+        Error,
+    > {
+        let peers_path = data_dir.map(|dir| dir.join(PEERS_FILE));
+        if let Some(ref path) = peers_path {
+            let known_peers = load_peers(path);
+            net_config
+                .boot_nodes
+                .extend(known_peers.into_iter().map(|addr| addr.to_string()));
+        }
         let version = [protocol::CURRENT_VERSION as u8];
         let registered = RegisteredProtocol::new(protocol_id, &version);
-        let protocol = Arc::new(Protocol::new(config, tx_callback));
+        let protocol = Arc::new(Protocol::new(config, tx_callback, rpc_handler));
+        protocol.subscribe(&TRANSACTIONS_TOPIC.to_string());
         let service = match start_service(net_config, Some(registered)) {
             Ok(s) => Arc::new(Mutex::new(s)),
             Err(e) => return Err(e.into()),
         };
-        let task = service_task(service.clone(), protocol.clone(), protocol_id).map_err(|e| {
-            debug!(target: "sub-libp2p", "service error: {:?}", e);
-        });
+        let bandwidth = Arc::new(BandwidthSink::default());
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let (command_sender, command_receiver) = mpsc::unbounded();
+        let task = service_task(
+            service.clone(),
+            protocol.clone(),
+            protocol_id,
+            peers_path,
+            bandwidth.clone(),
+            metrics.clone(),
+            command_receiver,
+        );
         Ok((
             Service {
                 network: service,
                 protocol,
+                bandwidth,
+                metrics,
             },
+            command_sender,
             task,
         ))
     }
+
+    /// Latest metrics snapshot, refreshed roughly once a second.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.lock().clone()
+    }
+}
+
+/// Read back the peer table persisted by a previous run, if any. Missing or
+/// unreadable files are treated as "nothing persisted yet" rather than an
+/// error, since that is the normal state for a node's first run.
+pub fn load_peers(path: &Path) -> Vec<Multiaddr> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return Vec::new(),
+    };
+    contents
+        .lines()
+        .filter_map(|line| line.parse::<Multiaddr>().ok())
+        .collect()
+}
+
+/// Snapshot the addresses the `NetworkService` currently knows about to
+/// `path`, so a future `load_peers` call can use them as extra dial targets.
+pub fn persist_peers(path: &Path, network_service: &NetworkService) {
+    let addresses: Vec<String> = network_service
+        .known_peers()
+        .into_iter()
+        .map(|addr| addr.to_string())
+        .collect();
+    if let Err(e) = fs::write(path, addresses.join("\n")) {
+        error!("failed to persist peer table to {:?}: {:?}", path, e);
+    }
+}
+
+/// Everything that can drive the network event loop forward: a raw network
+/// event, one of the periodic ticks, or a command pushed from outside.
+enum Action<T> {
+    Network(ServiceEvent),
+    MaintenanceTick,
+    MetricsTick,
+    Command(NetworkCommand<T>),
+    /// The command channel was dropped; time to wind down.
+    Shutdown,
 }
 
+/// Drives the network forward: selects whichever of the network event
+/// stream, the maintenance tick, the metrics tick, or `commands` fires next,
+/// and handles it. Runs until every `NetworkCommand` sender is dropped, at
+/// which point the known peer table is persisted (if configured) and the
+/// future resolves.
 pub fn service_task<T: Transaction>(
     network_service: Arc<Mutex<NetworkService>>,
     protocol: Arc<Protocol<T>>,
     protocol_id: ProtocolId,
-) -> impl Future<Item = (), Error = io::Error> {
-    // Interval for performing maintenance on the protocol handler.
-    let timer = Interval::new_interval(TICK_TIMEOUT)
-        .for_each({
-            let protocol = protocol.clone();
-            let network_service = network_service.clone();
-            move |_| {
-                protocol.maintain_peers(&mut NetSyncIo::new(&network_service, protocol_id));
-                Ok(())
+    peers_path: Option<PathBuf>,
+    bandwidth: Arc<BandwidthSink>,
+    metrics: Arc<Mutex<Metrics>>,
+    commands: mpsc::UnboundedReceiver<NetworkCommand<T>>,
+) -> impl Future<Item = (), Error = ()> {
+    let message_counters = Arc::new(MessageCounters::default());
+
+    let network_events = {
+        let network_service = network_service.clone();
+        stream::poll_fn(move || network_service.lock().poll())
+            .then(|res| match res {
+                Ok(event) => Ok(Some(Action::Network(event))),
+                Err(err) => {
+                    error!("network poll error: {:?}", err);
+                    Ok(None)
+                }
+            }).filter_map(|action: Option<Action<T>>| action)
+    };
+
+    let maintenance_ticks = Interval::new_interval(TICK_TIMEOUT)
+        .then(|res| match res {
+            Ok(_) => Ok(Some(Action::MaintenanceTick)),
+            Err(err) => {
+                error!("maintenance timer error: {:?}", err);
+                Ok(None)
+            }
+        }).filter_map(|action: Option<Action<T>>| action);
+
+    let metrics_ticks = Interval::new_interval(METRICS_TICK)
+        .then(|res| match res {
+            Ok(_) => Ok(Some(Action::MetricsTick)),
+            Err(err) => {
+                error!("metrics timer error: {:?}", err);
+                Ok(None)
             }
-        }).then(|res| {
-            match res {
-                Ok(()) => (),
-                Err(err) => error!("Error in the propagation timer: {:?}", err),
+        }).filter_map(|action: Option<Action<T>>| action);
+
+    // Once every sender is dropped the command stream ends; chain a single
+    // `Shutdown` action onto it so the combined loop below notices and winds
+    // down instead of running forever.
+    let commands = commands
+        .map(Action::Command)
+        .chain(stream::once(Ok(Action::Shutdown)));
+
+    let actions = network_events
+        .select(maintenance_ticks)
+        .select(metrics_ticks)
+        .select(commands);
+
+    let shutdown_network_service = network_service.clone();
+    actions
+        .for_each(move |action| {
+            let mut net_sync = NetSyncIo::new(&network_service, protocol_id, bandwidth.clone());
+            match action {
+                Action::Network(event) => {
+                    debug!(target: "sub-libp2p", "event: {:?}", event);
+                    match event {
+                        ServiceEvent::CustomMessage { node_index, data, .. } => {
+                            bandwidth.record_in(data.len());
+                            message_counters.custom_messages.fetch_add(1, Ordering::Relaxed);
+                            protocol.on_message(&mut net_sync, node_index, &data);
+                        }
+                        ServiceEvent::OpenedCustomProtocol { node_index, .. } => {
+                            message_counters.opened_protocols.fetch_add(1, Ordering::Relaxed);
+                            protocol.on_peer_connected(&mut net_sync, node_index);
+                        }
+                        ServiceEvent::ClosedCustomProtocol { node_index, .. } => {
+                            message_counters.closed_protocols.fetch_add(1, Ordering::Relaxed);
+                            protocol.on_peer_disconnected(node_index);
+                        }
+                        _ => debug!("TODO"),
+                    }
+                }
+                Action::MaintenanceTick => protocol.maintain_peers(&mut net_sync),
+                Action::MetricsTick => {
+                    *metrics.lock() = Metrics {
+                        peer_count: protocol.peer_count(),
+                        bytes_in: bandwidth.bytes_in(),
+                        bytes_out: bandwidth.bytes_out(),
+                        custom_messages: message_counters.custom_messages.load(Ordering::Relaxed),
+                        opened_protocols: message_counters.opened_protocols.load(Ordering::Relaxed),
+                        closed_protocols: message_counters.closed_protocols.load(Ordering::Relaxed),
+                        outstanding_requests: protocol.outstanding_request_count(),
+                    };
+                }
+                Action::Command(NetworkCommand::AnnounceTransaction(transaction)) => {
+                    protocol.publish(&mut net_sync, &TRANSACTIONS_TOPIC.to_string(), transaction);
+                }
+                Action::Command(NetworkCommand::Dial(addr)) => {
+                    if let Err(e) = network_service.lock().dial(addr.clone()) {
+                        error!("failed to dial {:?}: {:?}", addr, e);
+                    }
+                }
+                Action::Command(NetworkCommand::Disconnect(node_index)) => {
+                    net_sync.disconnect_peer(node_index);
+                }
+                Action::Command(NetworkCommand::ReportPeer(node_index, peer_action)) => {
+                    protocol.report_peer(&mut net_sync, node_index, peer_action);
+                }
+                Action::Command(NetworkCommand::SendRequest(node_index, request, response_sender)) => {
+                    let (_, receiver) = protocol.send_request(&mut net_sync, node_index, request);
+                    tokio::spawn(receiver.then(move |result| {
+                        if let Ok(response) = result {
+                            let _ = response_sender.send(response);
+                        }
+                        Ok(())
+                    }));
+                }
+                Action::Shutdown => return Err(()),
             };
             Ok(())
-        });
-    let network_service1 = network_service.clone();
-    let network = stream::poll_fn(move || network_service1.lock().poll()).for_each(move |event| {
-        let mut net_sync = NetSyncIo::new(&network_service, protocol_id);
-        debug!(target: "sub-libp2p", "event: {:?}", event);
-        match event {
-            ServiceEvent::CustomMessage {
-                node_index, data, ..
-            } => {
-                protocol.on_message(&mut net_sync, node_index, &data);
-            }
-            ServiceEvent::OpenedCustomProtocol { node_index, .. } => {
-                protocol.on_peer_connected(&mut net_sync, node_index);
-            }
-            ServiceEvent::ClosedCustomProtocol { node_index, .. } => {
-                protocol.on_peer_disconnected(node_index);
-            }
-            _ => {
-                debug!("TODO");
-                ()
-            }
-        };
-        Ok(())
-    });
-    let futures: Vec<Box<Future<Item = (), Error = io::Error> + Send>> =
-        vec![Box::new(timer), Box::new(network)];
-    futures::select_all(futures)
-        .and_then(move |_| {
+        }).then(move |_| {
             info!("Networking ended");
+            if let Some(path) = peers_path {
+                persist_peers(&path, &shutdown_network_service.lock());
+            }
             Ok(())
-        }).map_err(|(r, _, _)| r)
+        })
 }
 
 #[cfg(test)]
@@ -109,9 +309,13 @@ mod tests {
     use std::time;
     use test_utils::*;
 
-    fn create_services<T: Transaction>(
-        num_services: u32,
-    ) -> Vec<(Service<T>, impl Future<Item = (), Error = ()>)> {
+    type ServiceHandle<T> = (
+        Service<T>,
+        mpsc::UnboundedSender<NetworkCommand<T>>,
+        Box<Future<Item = (), Error = ()> + Send>,
+    );
+
+    fn create_services<T: Transaction>(num_services: u32) -> Vec<ServiceHandle<T>> {
         let base_address = "/ip4/127.0.0.1/tcp/".to_string();
         let base_port = rand::thread_rng().gen_range(30000, 60000);
         let mut addresses = Vec::new();
@@ -125,23 +329,29 @@ mod tests {
         let secret = create_secret();
         let root_config = test_config_with_secret(&addresses[0], vec![], secret);
         let tx_callback = |_| Ok(());
-        let root_service = Service::new(
+        let rpc_handler = |_| RpcResponse::Custom(vec![]);
+        let (root_service, root_commands, root_task) = Service::new(
             ProtocolConfig::default(),
             root_config,
             ProtocolId::default(),
             tx_callback,
+            rpc_handler,
+            None,
         ).unwrap();
         let boot_node = addresses[0].clone() + "/p2p/" + &raw_key_to_peer_id_str(secret);
-        let mut services = vec![root_service];
+        let mut services: Vec<ServiceHandle<T>> =
+            vec![(root_service, root_commands, Box::new(root_task))];
         for i in 1..num_services {
             let config = test_config(&addresses[i as usize], vec![boot_node.clone()]);
-            let service = Service::new(
+            let (service, commands, task) = Service::new(
                 ProtocolConfig::default(),
                 config,
                 ProtocolId::default(),
                 tx_callback,
+                rpc_handler,
+                None,
             ).unwrap();
-            services.push(service);
+            services.push((service, commands, Box::new(task)));
         }
         services
     }
@@ -150,17 +360,17 @@ mod tests {
     fn test_send_message() {
         let services = create_services(2);
         let mut runtime = tokio::runtime::Runtime::new().unwrap();
-        let (services, tasks): (Vec<_>, Vec<_>) = services.into_iter().unzip();
-        for task in tasks {
+        let mut commands = Vec::new();
+        for (_, command_sender, task) in services {
+            commands.push(command_sender);
             runtime.spawn(task);
         }
         thread::sleep(time::Duration::from_millis(1000));
-        for service in services {
-            for peer in service.protocol.sample_peers(1) {
-                let message = fake_tx_message();
-                let mut net_sync = NetSyncIo::new(&service.network, ProtocolId::default());
-                service.protocol.send_message(&mut net_sync, peer, &message);
-            }
+        for command_sender in &commands {
+            let message = fake_tx_message();
+            command_sender
+                .unbounded_send(NetworkCommand::AnnounceTransaction(message))
+                .unwrap();
         }
         thread::sleep(time::Duration::from_millis(1000));
     }